@@ -1,15 +1,18 @@
-use bytemuck::AnyBitPattern;
+use bytemuck::{AnyBitPattern, NoUninit};
 use clap::Parser;
-use csv::WriterBuilder;
+use csv::{ReaderBuilder, StringRecord, WriterBuilder};
 use fdr2csv_common::csv_header_serializer;
-use flate2::bufread::GzDecoder;
+use flate2::{
+    bufread::{GzDecoder, MultiGzDecoder},
+    Compression, GzBuilder, GzHeader,
+};
 use headers::{
     ap_raw_output, ap_sm_output, athr_out, base_elac_analog_outputs, base_elac_discrete_outputs,
     base_elac_out_bus, base_fac_analog_outputs, base_fac_bus, base_fac_discrete_outputs,
     base_sec_analog_outputs, base_sec_discrete_outputs, base_sec_out_bus, AdditionalData,
     EngineData,
 };
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use std::{
     fs::{File, OpenOptions},
     io::{prelude::*, BufReader, BufWriter, Error, ErrorKind},
@@ -39,10 +42,122 @@ struct Args {
     /// Print interface version of input file
     #[arg(short, long, default_value_t = false)]
     get_input_file_version: bool,
+    /// Input file is made up of multiple concatenated gzip members (e.g. a recording that was
+    /// paused/resumed or flushed mid-session)
+    #[arg(short, long, default_value_t = false)]
+    multi: bool,
+    /// Convert a CSV file (produced by this tool) back into a compressed FDR recording, instead
+    /// of the default FDR-to-CSV direction
+    #[arg(short, long, default_value_t = false)]
+    reverse: bool,
+    /// Gzip header mtime (seconds since Unix epoch) to embed in the output recording, used with
+    /// --reverse
+    #[arg(long)]
+    gz_mtime: Option<u32>,
+    /// Gzip header filename to embed in the output recording, used with --reverse
+    #[arg(long)]
+    gz_filename: Option<String>,
+    /// Gzip header comment to embed in the output recording, used with --reverse
+    #[arg(long)]
+    gz_comment: Option<String>,
+    /// Aircraft variant to embed in the EXTRA subfield of the output recording, used with
+    /// --reverse
+    #[arg(long, default_value = "A32NX")]
+    aircraft_variant: String,
+    /// Verify the gzip integrity trailer and reject a recording that ends mid-record, instead of
+    /// treating any read error as a clean end of file
+    #[arg(short, long, default_value_t = false)]
+    strict: bool,
 }
 
 const INTERFACE_VERSION: u64 = 3200001;
 
+// Gzip EXTRA subfield id (RFC 1952 2.3.1.1) we use to stash the interface version and aircraft
+// variant in the header, so a recording can be identified without decoding any records.
+const EXTRA_SUBFIELD_ID: [u8; 2] = *b"FD";
+
+// Either a plain file or one of the gzip decoders, so the rest of `fdr_to_csv` can read records
+// without caring which one it is, while still being able to reach the gzip header for single- and
+// multi-member (`--multi`) gzip inputs alike.
+enum Input {
+    Plain(BufReader<File>),
+    Gz(GzDecoder<BufReader<File>>),
+    MultiGz(MultiGzDecoder<BufReader<File>>),
+}
+
+impl Read for Input {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        match self {
+            Input::Plain(reader) => reader.read(buf),
+            Input::Gz(reader) => reader.read(buf),
+            Input::MultiGz(reader) => reader.read(buf),
+        }
+    }
+}
+
+impl Input {
+    // Plain (non-compressed) input has no gzip header to report.
+    fn header(&self) -> Option<&GzHeader> {
+        match self {
+            Input::Plain(_) => None,
+            Input::Gz(reader) => reader.header(),
+            Input::MultiGz(reader) => reader.header(),
+        }
+    }
+}
+
+// Wraps a reader and counts how many bytes have passed through it, for `--strict`'s record-boundary check.
+struct CountingReader<R> {
+    inner: R,
+    bytes_read: u64,
+}
+
+impl<R: Read> CountingReader<R> {
+    fn new(inner: R) -> Self {
+        CountingReader {
+            inner,
+            bytes_read: 0,
+        }
+    }
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        let n = self.inner.read(buf)?;
+        self.bytes_read += n as u64;
+        Ok(n)
+    }
+}
+
+// Interface version and aircraft variant carried in our EXTRA subfield.
+struct FdrExtra {
+    interface_version: u64,
+    aircraft_variant: String,
+}
+
+// Find our `FD` subfield inside a gzip EXTRA field and decode its payload: an 8-byte
+// little-endian interface version followed by the aircraft variant string.
+fn parse_fdr_extra(extra: &[u8]) -> Option<FdrExtra> {
+    let mut remaining = extra;
+
+    while remaining.len() >= 4 {
+        let id = [remaining[0], remaining[1]];
+        let len = u16::from_le_bytes([remaining[2], remaining[3]]) as usize;
+        let data = remaining.get(4..4 + len)?;
+
+        if id == EXTRA_SUBFIELD_ID && data.len() >= mem::size_of::<u64>() {
+            return Some(FdrExtra {
+                interface_version: u64::from_le_bytes(data[..8].try_into().ok()?),
+                aircraft_variant: String::from_utf8_lossy(&data[8..]).into_owned(),
+            });
+        }
+
+        remaining = &remaining[4 + len..];
+    }
+
+    None
+}
+
 // Read number of bytes specified by the size of T from the binary file
 fn read_bytes<T: AnyBitPattern>(reader: &mut impl Read) -> Result<T, Error> {
     let size = mem::size_of::<T>();
@@ -59,8 +174,45 @@ fn read_bytes<T: AnyBitPattern>(reader: &mut impl Read) -> Result<T, Error> {
     Ok(*res)
 }
 
-// A single FDR record
-#[derive(Serialize, Default)]
+// Write a value's bytes to the binary file, mirroring `read_bytes` for the reverse direction
+fn write_bytes<T: NoUninit>(writer: &mut impl Write, value: T) -> Result<(), Error> {
+    writer.write_all(bytemuck::bytes_of(&value))
+}
+
+// Build the payload for our `FD` EXTRA subfield: an 8-byte little-endian interface version
+// followed by the aircraft variant string.
+fn build_fdr_extra(aircraft_variant: &str) -> Result<Vec<u8>, Error> {
+    let mut data = Vec::with_capacity(mem::size_of::<u64>() + aircraft_variant.len());
+    data.extend_from_slice(&INTERFACE_VERSION.to_le_bytes());
+    data.extend_from_slice(aircraft_variant.as_bytes());
+
+    // Reject a payload too long for the u16 length prefixes (RFC 1952 2.3.1.1) instead of truncating
+    // it: both the subfield's own length and the whole EXTRA field's XLEN, which also counts the
+    // 4-byte subfield id/length.
+    let data_len = u16::try_from(4 + data.len())
+        .map(|_| data.len() as u16)
+        .map_err(|_| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "--aircraft-variant is too long ({} byte(s)); the FD EXTRA subfield only fits an aircraft variant of up to {} byte(s)",
+                    aircraft_variant.len(),
+                    u16::MAX as usize - 4 - mem::size_of::<u64>(),
+                ),
+            )
+        })?;
+
+    let mut extra = Vec::with_capacity(4 + data.len());
+    extra.extend_from_slice(&EXTRA_SUBFIELD_ID);
+    extra.extend_from_slice(&data_len.to_le_bytes());
+    extra.extend_from_slice(&data);
+    Ok(extra)
+}
+
+// A single FDR record. `repr(C)` plus `AnyBitPattern`/`NoUninit` lets us read and write the whole
+// record as one contiguous buffer instead of issuing a separate read/write per field.
+#[derive(Copy, Clone, Serialize, Deserialize, Default, AnyBitPattern, NoUninit)]
+#[repr(C)]
 struct FdrData {
     elac_1_bus: base_elac_out_bus,
     elac_1_discrete: base_elac_discrete_outputs,
@@ -90,55 +242,95 @@ struct FdrData {
     data: AdditionalData,
 }
 
-// These are helper functions to read in a whole FDR record.
-fn read_record(reader: &mut impl Read) -> Result<FdrData, Error> {
-    Ok(FdrData {
-        elac_1_bus: read_bytes::<base_elac_out_bus>(reader)?,
-        elac_1_discrete: read_bytes::<base_elac_discrete_outputs>(reader)?,
-        elac_1_analog: read_bytes::<base_elac_analog_outputs>(reader)?,
-        elac_2_bus: read_bytes::<base_elac_out_bus>(reader)?,
-        elac_2_discrete: read_bytes::<base_elac_discrete_outputs>(reader)?,
-        elac_2_analog: read_bytes::<base_elac_analog_outputs>(reader)?,
-        sec_1_bus: read_bytes::<base_sec_out_bus>(reader)?,
-        sec_1_discrete: read_bytes::<base_sec_discrete_outputs>(reader)?,
-        sec_1_analog: read_bytes::<base_sec_analog_outputs>(reader)?,
-        sec_2_bus: read_bytes::<base_sec_out_bus>(reader)?,
-        sec_2_discrete: read_bytes::<base_sec_discrete_outputs>(reader)?,
-        sec_2_analog: read_bytes::<base_sec_analog_outputs>(reader)?,
-        sec_3_bus: read_bytes::<base_sec_out_bus>(reader)?,
-        sec_3_discrete: read_bytes::<base_sec_discrete_outputs>(reader)?,
-        sec_3_analog: read_bytes::<base_sec_analog_outputs>(reader)?,
-        fac_1_bus: read_bytes::<base_fac_bus>(reader)?,
-        fac_1_discrete: read_bytes::<base_fac_discrete_outputs>(reader)?,
-        fac_1_analog: read_bytes::<base_fac_analog_outputs>(reader)?,
-        fac_2_bus: read_bytes::<base_fac_bus>(reader)?,
-        fac_2_discrete: read_bytes::<base_fac_discrete_outputs>(reader)?,
-        fac_2_analog: read_bytes::<base_fac_analog_outputs>(reader)?,
-        ap_sm: read_bytes::<ap_sm_output>(reader)?,
-        ap_law: read_bytes::<ap_raw_output>(reader)?,
-        athr: read_bytes::<athr_out>(reader)?,
-        engine: read_bytes::<EngineData>(reader)?,
-        data: read_bytes::<AdditionalData>(reader)?,
-    })
+// Total size in bytes of a single FDR record
+fn record_size() -> usize {
+    mem::size_of::<FdrData>()
+}
+
+// Read a whole FDR record in a single `read_exact`, reusing the caller's scratch `FdrData` so
+// neither the buffer nor its alignment has to be rebuilt per record.
+fn read_record(reader: &mut impl Read, buf: &mut FdrData) -> Result<FdrData, Error> {
+    reader.read_exact(bytemuck::bytes_of_mut(buf))?;
+    Ok(*buf)
+}
+
+// The inverse of `read_record`: write a whole FDR record in a single call.
+fn write_record(writer: &mut impl Write, record: &FdrData) -> Result<(), Error> {
+    writer.write_all(bytemuck::bytes_of(record))
+}
+
+// Check that a CSV header matches the columns `csv_header_serializer` would generate for `FdrData`.
+fn validate_csv_header(actual: &StringRecord, delimiter: char) -> Result<(), Error> {
+    let expected = csv_header_serializer::to_string(&FdrData::default(), delimiter)
+        .map_err(|_| Error::new(ErrorKind::Other, "Failed to generate header."))?;
+    let expected: Vec<&str> = expected.trim_end().split(delimiter).collect();
+
+    if actual.iter().ne(expected.iter().copied()) {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "CSV header does not match the current FdrData schema",
+        ));
+    }
+
+    Ok(())
 }
 
 fn main() -> Result<(), std::io::Error> {
     // Parse CLI arguments
     let args = Args::parse();
 
+    if args.reverse {
+        csv_to_fdr(&args)
+    } else {
+        fdr_to_csv(&args)
+    }
+}
+
+// Convert a compressed (or raw) FDR recording into CSV.
+fn fdr_to_csv(args: &Args) -> Result<(), std::io::Error> {
     // Open the input file
     let in_file = File::open(args.input.trim())
         .map_err(|e| std::io::Error::new(e.kind(), "Failed to open input file!"))?;
 
-    // Create Gzip Reader
-    let mut reader: Box<dyn Read> = if args.no_compression {
-        Box::new(BufReader::new(in_file))
+    // Create Gzip Reader. A `--multi` recording is a stream of concatenated gzip members (each
+    // pause/resume or mid-session flush starts a new member); `MultiGzDecoder` transparently
+    // continues decoding across member boundaries instead of stopping at the end of the first
+    // one, so `read_record` keeps producing records for the whole file.
+    let mut input = if args.no_compression {
+        Input::Plain(BufReader::new(in_file))
+    } else if args.multi {
+        Input::MultiGz(MultiGzDecoder::new(BufReader::new(in_file)))
     } else {
-        Box::new(GzDecoder::new(BufReader::new(in_file)))
+        Input::Gz(GzDecoder::new(BufReader::new(in_file)))
+    };
+
+    // Pull out whatever the gzip header has to offer before we start consuming the record
+    // stream: filename, comment, mtime, and our own EXTRA subfield with the interface version and
+    // aircraft variant.
+    let (filename, comment, mtime, extra) = match input.header() {
+        Some(header) => (
+            header
+                .filename()
+                .map(|bytes| String::from_utf8_lossy(bytes).into_owned()),
+            header
+                .comment()
+                .map(|bytes| String::from_utf8_lossy(bytes).into_owned()),
+            Some(header.mtime()),
+            header.extra().and_then(parse_fdr_extra),
+        ),
+        None => (None, None, None, None),
     };
 
-    // Read file version
-    let file_format_version = read_bytes::<u64>(&mut reader)?;
+    // Read file version, preferring the one from the EXTRA subfield (if present) when the caller
+    // only wants the version, since that avoids consuming any of the record stream.
+    let file_format_version = if args.get_input_file_version {
+        match &extra {
+            Some(extra) => extra.interface_version,
+            None => read_bytes::<u64>(&mut input)?,
+        }
+    } else {
+        read_bytes::<u64>(&mut input)?
+    };
 
     // Print or check file version
     if args.get_input_file_version {
@@ -153,6 +345,28 @@ fn main() -> Result<(), std::io::Error> {
         ));
     }
 
+    // Print recording metadata carried in the gzip header, if any
+    if let Some(filename) = &filename {
+        println!("Recording filename: {filename}");
+    }
+    if let Some(comment) = &comment {
+        println!("Recording comment: {comment}");
+    }
+    if let Some(mtime) = mtime {
+        println!("Recording mtime (unix epoch): {mtime}");
+    }
+    if let Some(extra) = &extra {
+        println!(
+            "Recording metadata: interface version '{}', aircraft variant '{}'",
+            extra.interface_version, extra.aircraft_variant
+        );
+    }
+
+    // Print struct size, if requested
+    if args.print_struct_size {
+        println!("FdrData record size is {} bytes", record_size());
+    }
+
     // Print info on conversion start
     println!(
         "Converting from '{}' to '{}' with interface version '{}' and delimiter '{}'",
@@ -182,8 +396,122 @@ fn main() -> Result<(), std::io::Error> {
         .has_headers(false)
         .from_writer(buf_writer);
 
-    while let Ok(fdr_data) = read_record(&mut reader) {
-        writer.serialize(&fdr_data)?;
+    // Wrap so `--strict` can tell a clean end of stream from one that stopped mid-record.
+    let mut input = CountingReader::new(input);
+
+    // Reused across every iteration so reading records doesn't allocate.
+    let mut record_buf = FdrData::default();
+
+    loop {
+        match read_record(&mut input, &mut record_buf) {
+            Ok(fdr_data) => {
+                writer.serialize(fdr_data)?;
+
+                counter += 1;
+
+                if counter % 1000 == 0 {
+                    print!("Processed {counter} entries...\r");
+                    std::io::stdout().flush()?;
+                }
+            }
+            Err(err) => {
+                if args.strict {
+                    // A gzip trailer mismatch or other I/O failure surfaces as something other
+                    // than `UnexpectedEof` - report it instead of treating it as a clean EOF.
+                    if err.kind() != ErrorKind::UnexpectedEof {
+                        return Err(std::io::Error::new(
+                            err.kind(),
+                            format!(
+                                "Recording failed integrity check after {counter} complete record(s): {err}",
+                            ),
+                        ));
+                    }
+
+                    let dangling_bytes = input.bytes_read % record_size() as u64;
+                    if dangling_bytes != 0 {
+                        return Err(std::io::Error::new(
+                            ErrorKind::UnexpectedEof,
+                            format!(
+                                "Truncated recording: {dangling_bytes} dangling byte(s) after {counter} complete record(s)",
+                            ),
+                        ));
+                    }
+
+                    // `UnexpectedEof` on a record boundary isn't proof the gzip trailer was
+                    // actually validated; probe with one more read to be sure.
+                    if input.read(&mut [0u8; 1]).is_err() {
+                        return Err(std::io::Error::new(
+                            ErrorKind::UnexpectedEof,
+                            format!(
+                                "Truncated recording: gzip trailer is missing or incomplete after {counter} complete record(s)",
+                            ),
+                        ));
+                    }
+                }
+
+                break;
+            }
+        }
+    }
+
+    println!("Processed {counter} entries...");
+
+    Result::Ok(())
+}
+
+// The inverse of `fdr_to_csv`: parse a CSV produced by this tool and re-encode it as a compressed
+// FDR recording.
+fn csv_to_fdr(args: &Args) -> Result<(), std::io::Error> {
+    // Open the input file
+    let in_file = File::open(args.input.trim())
+        .map_err(|e| Error::new(e.kind(), "Failed to open input file!"))?;
+
+    // `has_headers(false)` so `deserialize()` maps columns positionally instead of by field name,
+    // since `FdrData`'s own fields are nested structs with no single matching column name.
+    let mut reader = ReaderBuilder::new()
+        .delimiter(args.delimiter as u8)
+        .has_headers(false)
+        .from_reader(BufReader::new(in_file));
+
+    let mut header = StringRecord::new();
+    reader.read_record(&mut header)?;
+    validate_csv_header(&header, args.delimiter)?;
+
+    // Open or create output file in truncate mode
+    let out_file = OpenOptions::new()
+        .write(true)
+        .truncate(true)
+        .create(true)
+        .open(args.output.trim())
+        .map_err(|e| Error::new(e.kind(), "Failed to open output file!"))?;
+
+    // Build the gzip header: caller-supplied filename/comment/mtime plus our EXTRA subfield.
+    let mut builder = GzBuilder::new().extra(build_fdr_extra(&args.aircraft_variant)?);
+    if let Some(filename) = &args.gz_filename {
+        builder = builder.filename(filename.as_str());
+    }
+    if let Some(comment) = &args.gz_comment {
+        builder = builder.comment(comment.as_str());
+    }
+    if let Some(mtime) = args.gz_mtime {
+        builder = builder.mtime(mtime);
+    }
+
+    let mut encoder = builder.write(BufWriter::new(out_file), Compression::default());
+
+    // Print info on conversion start
+    println!(
+        "Converting from '{}' to '{}' with interface version '{}' and delimiter '{}'",
+        args.input, args.output, INTERFACE_VERSION, args.delimiter
+    );
+
+    write_bytes(&mut encoder, INTERFACE_VERSION)?;
+
+    let mut counter = 0;
+
+    for result in reader.deserialize() {
+        let record: FdrData = result?;
+        write_record(&mut encoder, &record)?;
 
         counter += 1;
 
@@ -193,7 +521,9 @@ fn main() -> Result<(), std::io::Error> {
         }
     }
 
+    encoder.finish()?;
+
     println!("Processed {counter} entries...");
 
-    Result::Ok(())
+    Ok(())
 }